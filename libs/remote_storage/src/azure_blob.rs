@@ -0,0 +1,442 @@
+//! Azure Blob Storage implementation of [`crate::RemoteStorage`].
+
+use std::env;
+use std::num::{NonZeroU32, NonZeroUsize};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use azure_core::request_options::IfMatchCondition;
+use azure_storage::{prelude::*, StorageCredentials};
+use azure_storage_blobs::blob::{BlobBlockType, BlockList};
+use azure_storage_blobs::prelude::*;
+use camino::Utf8Path;
+use futures::stream::StreamExt;
+use futures::FutureExt;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tracing::debug;
+
+use crate::{
+    AzureAuthMethod, AzureConfig, BoxStream, Download, DownloadPrecondition, ListResult,
+    ListingMode, ObjectMeta, PreconditionError, RemotePath, RemoteStorage, StorageMetadata,
+    UploadPrecondition,
+};
+
+/// Devstorage account name/key baked into every Azurite emulator instance. These are
+/// publicly documented well-known values (not a secret), the Azure equivalent of the
+/// AWS `test`/`test` credentials used against `localstack`.
+const AZURITE_ACCOUNT: &str = "devstoreaccount1";
+const AZURITE_ACCOUNT_KEY: &str = "Eby8vdM02xNOcqFlqUwJPLlmEtlCDXJ1OUzFT50uSRZ6IFsuFq2UVErCz4I6tq/K1SZFPTOtr/KBHBeksoGMGw==";
+
+/// Default block size for [`AzureBlobStorage::upload_multipart`] when the config
+/// doesn't set `multipart_chunk_size`.
+const DEFAULT_MULTIPART_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+
+pub struct AzureBlobStorage {
+    client: ContainerClient,
+    prefix_in_container: Option<String>,
+    max_keys_per_list_response: Option<NonZeroU32>,
+    concurrency_limit: usize,
+    multipart_chunk_size: usize,
+}
+
+impl AzureBlobStorage {
+    pub fn new(config: &AzureConfig) -> Result<Self> {
+        let account = match env::var("AZURE_STORAGE_ACCOUNT") {
+            Ok(account) => account,
+            // Only the Azurite emulator has a fixed, well-known account name; a real
+            // deployment has no sensible default; missing the env var there should be
+            // a clear error, not a silent client pointed at an account that doesn't
+            // exist.
+            Err(_) if config.endpoint.is_some() => AZURITE_ACCOUNT.to_string(),
+            Err(e) => return Err(e).context("`AZURE_STORAGE_ACCOUNT` env var is not set"),
+        };
+
+        let credentials = if config.endpoint.is_some() {
+            // Local/CI Azurite emulator: always uses the well-known devstoreaccount1
+            // key, never real credentials, so tests never need a live Azure account,
+            // regardless of which `auth` method a real deployment would use here.
+            debug!("using Azurite emulator at {:?}", config.endpoint);
+            StorageCredentials::access_key(AZURITE_ACCOUNT, AZURITE_ACCOUNT_KEY.to_string())
+        } else {
+            Self::credentials_for(&config.auth, &account)?
+        };
+
+        let mut builder = ClientBuilder::new(account, credentials);
+        if let Some(endpoint) = &config.endpoint {
+            builder = builder.cloud_location(CloudLocation::Custom {
+                account: AZURITE_ACCOUNT.to_string(),
+                uri: endpoint.clone(),
+            });
+        }
+
+        let client = builder.container_client(config.container_name.clone());
+
+        let max_keys_per_list_response = match config.max_keys_per_list_response {
+            Some(limit) => Some(
+                NonZeroU32::new(u32::try_from(limit).context("max_keys_per_list_response")?)
+                    .context("max_keys_per_list_response must be positive")?,
+            ),
+            None => None,
+        };
+
+        let multipart_chunk_size = config
+            .multipart_chunk_size
+            .map(NonZeroUsize::get)
+            .unwrap_or(DEFAULT_MULTIPART_CHUNK_SIZE);
+
+        Ok(Self {
+            client,
+            prefix_in_container: config.prefix_in_container.clone(),
+            max_keys_per_list_response,
+            concurrency_limit: config.concurrency_limit.get(),
+            multipart_chunk_size,
+        })
+    }
+
+    /// Build the `StorageCredentials` for `auth`. Only reached for real Azure, never
+    /// for the Azurite emulator, which always uses its own well-known account key.
+    fn credentials_for(auth: &AzureAuthMethod, account: &str) -> Result<StorageCredentials> {
+        match auth {
+            AzureAuthMethod::AccountKey => {
+                let access_key = env::var("AZURE_STORAGE_ACCESS_KEY").context(
+                    "`AZURE_STORAGE_ACCESS_KEY` env var is not set, but real Azure is enabled",
+                )?;
+                Ok(StorageCredentials::access_key(
+                    account.to_string(),
+                    access_key,
+                ))
+            }
+            AzureAuthMethod::SasToken(token) => {
+                StorageCredentials::sas_token(token.clone()).context("invalid Azure SAS token")
+            }
+            AzureAuthMethod::ManagedIdentity => {
+                let credential = azure_identity::create_default_credential()
+                    .context("constructing managed/workload identity credential")?;
+                Ok(StorageCredentials::token_credential(credential))
+            }
+        }
+    }
+
+    fn relative_path_to_name(&self, path: &RemotePath) -> String {
+        let path_string = path.get_path().as_str();
+        match &self.prefix_in_container {
+            Some(prefix) if prefix.ends_with('/') => format!("{prefix}{path_string}"),
+            Some(prefix) => format!("{prefix}/{path_string}"),
+            None => path_string.to_string(),
+        }
+    }
+
+    fn name_to_relative_path(&self, name: &str) -> RemotePath {
+        let relative = match &self.prefix_in_container {
+            Some(prefix) => name.strip_prefix(prefix.trim_end_matches('/')).unwrap_or(name),
+            None => name,
+        };
+        let relative = relative.trim_start_matches('/');
+        RemotePath::new(Utf8Path::new(relative)).expect("list response name must be a valid path")
+    }
+
+}
+
+/// `true` if `error` is Azure's way of saying a conditional header (`If-Match` /
+/// `If-None-Match`) didn't hold: HTTP 412 Precondition Failed.
+fn is_precondition_failed(error: &azure_core::Error) -> bool {
+    matches!(
+        error.kind(),
+        azure_core::error::ErrorKind::HttpResponse { status, .. } if status.as_u16() == 412
+    )
+}
+
+/// Read up to `chunk_size` bytes from `from`, looping until the buffer is full or the
+/// reader hits EOF. The returned buffer is shorter than `chunk_size` only at EOF.
+async fn read_chunk(from: &mut (impl AsyncRead + Unpin), chunk_size: usize) -> Result<Vec<u8>> {
+    let mut buf = vec![0u8; chunk_size];
+    let mut filled = 0;
+    while filled < chunk_size {
+        let n = from
+            .read(&mut buf[filled..])
+            .await
+            .context("reading upload body")?;
+        if n == 0 {
+            break;
+        }
+        filled += n;
+    }
+    buf.truncate(filled);
+    Ok(buf)
+}
+
+/// Deterministic, strictly-increasing block ID for the `idx`-th block of a multipart
+/// upload -- fixed width so blocks sort correctly if ever listed/inspected by a tool.
+fn block_id(idx: usize) -> BlockId {
+    BlockId::new(format!("{idx:032x}"))
+}
+
+#[async_trait]
+impl RemoteStorage for AzureBlobStorage {
+    /// Drive the underlying Azure SDK's continuation-token-based pagination one
+    /// response at a time, translating each page straight into a [`ListResult`] as it
+    /// arrives -- the whole listing is never buffered in memory at once, only whatever
+    /// the Azure SDK itself holds for the page in flight.
+    fn list_streaming<'a>(
+        &'a self,
+        mode: ListingMode,
+        prefix: Option<&'a RemotePath>,
+    ) -> BoxStream<'a, Result<ListResult>> {
+        let mut builder = self.client.list_blobs();
+        if let Some(limit) = self.max_keys_per_list_response {
+            builder = builder.max_results(MaxResults::new(limit));
+        }
+        if mode == ListingMode::WithDelimiter {
+            builder = builder.delimiter("/".to_string());
+        }
+        let mut full_prefix = match prefix {
+            Some(prefix) => self.relative_path_to_name(prefix),
+            None => self.prefix_in_container.clone().unwrap_or_default(),
+        };
+        // A delimiter listing groups keys below the prefix only if the prefix ends with
+        // the delimiter; otherwise Azure returns just the prefix itself as one entry.
+        if mode == ListingMode::WithDelimiter && !full_prefix.is_empty() && !full_prefix.ends_with('/') {
+            full_prefix.push('/');
+        }
+        if !full_prefix.is_empty() {
+            builder = builder.prefix(full_prefix);
+        }
+
+        Box::pin(builder.into_stream().map(move |page| {
+            let page = page.context("listing Azure blobs")?;
+            let objects = page
+                .blobs
+                .blobs()
+                .map(|blob| ObjectMeta {
+                    path: self.name_to_relative_path(&blob.name),
+                    size: blob.properties.content_length,
+                    last_modified: blob.properties.last_modified.into(),
+                    etag: blob.properties.etag.to_string(),
+                })
+                .collect();
+            let common_prefixes = page
+                .blobs
+                .prefixes()
+                .map(|blob_prefix| self.name_to_relative_path(&blob_prefix.name))
+                .collect();
+            Ok(ListResult {
+                objects,
+                common_prefixes,
+            })
+        }))
+    }
+
+    async fn head_object(&self, path: &RemotePath) -> Result<ObjectMeta> {
+        let name = self.relative_path_to_name(path);
+        let response = self
+            .client
+            .blob_client(name)
+            .get_properties()
+            .into_future()
+            .await
+            .with_context(|| format!("fetching metadata for {path:?} from Azure"))?;
+        let properties = response.blob.properties;
+        Ok(ObjectMeta {
+            path: path.clone(),
+            size: properties.content_length,
+            last_modified: properties.last_modified.into(),
+            etag: properties.etag.to_string(),
+        })
+    }
+
+    async fn upload(
+        &self,
+        from: impl AsyncRead + Unpin + Send + Sync + 'static,
+        from_size_bytes: usize,
+        to: &RemotePath,
+        metadata: Option<StorageMetadata>,
+        precondition: Option<UploadPrecondition>,
+    ) -> Result<()> {
+        let name = self.relative_path_to_name(to);
+        let blob_client = self.client.blob_client(name);
+
+        let mut buf = Vec::with_capacity(from_size_bytes);
+        let mut from = from;
+        from.read_to_end(&mut buf).await.context("reading upload body")?;
+
+        let mut builder = blob_client.put_block_blob(buf);
+        if let Some(metadata) = metadata {
+            let mut azure_metadata = Metadata::new();
+            for (k, v) in metadata.0 {
+                azure_metadata.insert(k, v);
+            }
+            builder = builder.metadata(azure_metadata);
+        }
+        if let Some(UploadPrecondition::CreateOnly) = precondition {
+            builder = builder.if_match(IfMatchCondition::NotMatch("*".to_string()));
+        }
+        match builder.into_future().await {
+            Ok(_) => Ok(()),
+            Err(e) if is_precondition_failed(&e) => {
+                Err(PreconditionError { path: to.clone() }.into())
+            }
+            Err(e) => Err(e).with_context(|| format!("uploading {to:?} to Azure")),
+        }
+    }
+
+    async fn upload_multipart(
+        &self,
+        from: impl AsyncRead + Unpin + Send + Sync + 'static,
+        to: &RemotePath,
+        metadata: Option<StorageMetadata>,
+    ) -> Result<()> {
+        let chunk_size = self.multipart_chunk_size;
+        let mut from = from;
+        let first_chunk = read_chunk(&mut from, chunk_size).await?;
+        if first_chunk.len() < chunk_size {
+            // The whole object fits in one chunk: a plain PUT is simpler and cheaper
+            // than staging and committing a one-block list.
+            let len = first_chunk.len();
+            return self
+                .upload(std::io::Cursor::new(first_chunk), len, to, metadata, None)
+                .await;
+        }
+
+        let name = self.relative_path_to_name(to);
+        let blob_client = self.client.blob_client(name);
+
+        // Stage the first block, then pull the remaining chunks out of `from` one at a
+        // time -- bounding how much of the object is buffered in memory at once to
+        // `concurrency_limit` chunks in flight -- staging each as an uncommitted block
+        // with bounded concurrency via `buffer_unordered`, same pattern as
+        // `delete_objects`. Block 0 is folded into the same `buffer_unordered`
+        // pipeline as the rest (rather than awaited up front) so it actually runs
+        // concurrently with them instead of serially before them.
+        let first_block = {
+            let blob_client = blob_client.clone();
+            let block_id = block_id(0);
+            async move {
+                blob_client
+                    .put_block(block_id.clone(), first_chunk)
+                    .into_future()
+                    .await
+                    .context("staging block 0")?;
+                Ok((0usize, block_id))
+            }
+            .boxed()
+        };
+
+        let rest_blocks = futures::stream::unfold(Some((from, 1usize)), move |state| async move {
+            let (mut from, idx) = state?;
+            match read_chunk(&mut from, chunk_size).await {
+                Ok(chunk) if chunk.is_empty() => None,
+                Ok(chunk) => Some((Ok((idx, chunk)), Some((from, idx + 1)))),
+                Err(e) => Some((Err(e), None)),
+            }
+        })
+        .map(|item| {
+            let blob_client = blob_client.clone();
+            async move {
+                let (idx, chunk) = item?;
+                let id = block_id(idx);
+                blob_client
+                    .put_block(id.clone(), chunk)
+                    .into_future()
+                    .await
+                    .with_context(|| format!("staging block {idx}"))?;
+                Ok((idx, id))
+            }
+            .boxed()
+        });
+
+        let staged: Vec<Result<(usize, BlockId)>> =
+            futures::stream::once(async { first_block })
+                .chain(rest_blocks)
+                .buffer_unordered(self.concurrency_limit)
+                .collect()
+                .await;
+
+        let mut blocks: Vec<(usize, BlockId)> = staged.into_iter().collect::<Result<_>>()?;
+        blocks.sort_by_key(|(idx, _)| *idx);
+
+        let mut block_list = BlockList::default();
+        for (_, id) in blocks {
+            block_list.blocks.push(BlobBlockType::new_uncommitted(id));
+        }
+
+        let mut builder = blob_client.put_block_list(block_list);
+        if let Some(metadata) = metadata {
+            let mut azure_metadata = Metadata::new();
+            for (k, v) in metadata.0 {
+                azure_metadata.insert(k, v);
+            }
+            builder = builder.metadata(azure_metadata);
+        }
+        builder
+            .into_future()
+            .await
+            .with_context(|| format!("committing block list for {to:?} in Azure"))?;
+        Ok(())
+    }
+
+    async fn download_byte_range(
+        &self,
+        from: &RemotePath,
+        start_inclusive: u64,
+        end_exclusive: Option<u64>,
+        precondition: Option<DownloadPrecondition>,
+    ) -> Result<Download> {
+        let name = self.relative_path_to_name(from);
+        let blob_client = self.client.blob_client(name);
+
+        let mut builder = blob_client.get();
+        let range = match end_exclusive {
+            Some(end) => Range::Range(start_inclusive..end),
+            None => Range::RangeFrom(start_inclusive..),
+        };
+        builder = builder.range(range);
+        if let Some(DownloadPrecondition::IfMatch(etag)) = precondition {
+            builder = builder.if_match(IfMatchCondition::Match(etag));
+        }
+
+        let mut stream = builder.into_stream();
+        let chunk = match stream.next().await {
+            None => anyhow::bail!("{from:?} not found in Azure"),
+            Some(Err(e)) if is_precondition_failed(&e) => {
+                return Err(PreconditionError { path: from.clone() }.into())
+            }
+            Some(chunk) => chunk.with_context(|| format!("downloading {from:?} from Azure"))?,
+        };
+
+        let reader = chunk
+            .data
+            .collect()
+            .await
+            .context("collecting Azure download body")?;
+        Ok(Download {
+            download_stream: Box::pin(std::io::Cursor::new(reader.to_vec())),
+        })
+    }
+
+    async fn delete(&self, path: &RemotePath) -> Result<()> {
+        let name = self.relative_path_to_name(path);
+        match self.client.blob_client(name).delete().into_future().await {
+            Ok(_) => Ok(()),
+            // Deleting something that's already gone is not an error: callers (e.g. the
+            // pageserver's own cleanup code) routinely retry deletes after a crash.
+            Err(e) if matches!(e.kind(), azure_core::error::ErrorKind::HttpResponse { status, .. } if status.as_u16() == 404) => {
+                Ok(())
+            }
+            Err(e) => Err(e).with_context(|| format!("deleting {path:?} from Azure")),
+        }
+    }
+
+    async fn delete_objects(&self, paths: &[RemotePath]) -> Result<()> {
+        // Bounded concurrency so a large batch delete doesn't open one connection per
+        // object at once; Azure has no native batch-delete API for blobs the way S3 does.
+        let results = futures::stream::iter(paths)
+            .map(|path| self.delete(path))
+            .buffer_unordered(self.concurrency_limit)
+            .collect::<Vec<_>>()
+            .await;
+        for result in results {
+            result?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,402 @@
+//! A generic abstraction over remote object storage, used by the pageserver (and other
+//! components) to upload and fetch layer files, checkpoints, and other durable state that
+//! doesn't live on local disk.
+//!
+//! [`GenericRemoteStorage`] is constructed once from a [`RemoteStorageConfig`] and then
+//! shared (behind an `Arc`) by whatever needs to talk to the configured backend. Today the
+//! only backend implemented here is [`azure_blob::AzureBlobStorage`]; other kinds (S3,
+//! local-disk-as-remote for tests) are intentionally not part of this crate snapshot.
+
+pub mod azure_blob;
+
+use std::fmt::Debug;
+use std::num::{NonZeroU32, NonZeroUsize};
+use std::pin::Pin;
+
+use anyhow::Result;
+use camino::{Utf8Path, Utf8PathBuf};
+use tokio::io::AsyncRead;
+
+use azure_blob::AzureBlobStorage;
+
+/// Additional metadata stashed alongside an object on upload (e.g. Azure blob metadata
+/// headers). Opaque key/value pairs; interpretation is entirely up to the caller.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StorageMetadata(pub std::collections::HashMap<String, String>);
+
+/// A path relative to the root of whatever prefix the storage backend was configured
+/// with. Always relative and always uses `/` as a separator, regardless of the local
+/// platform, since it corresponds to an object key, not a filesystem path.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RemotePath(Utf8PathBuf);
+
+impl RemotePath {
+    pub fn new(relative_path: &Utf8Path) -> Result<Self> {
+        anyhow::ensure!(
+            relative_path.is_relative(),
+            "remote path {relative_path:?} has to be relative"
+        );
+        Ok(Self(relative_path.to_path_buf()))
+    }
+
+    pub fn join(&self, segment: &Utf8Path) -> Self {
+        Self(self.0.join(segment))
+    }
+
+    pub fn get_path(&self) -> &Utf8Path {
+        &self.0
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+}
+
+impl std::fmt::Display for RemotePath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Display::fmt(&self.0, f)
+    }
+}
+
+/// The result of a successful download: a stream of the object's bytes. Object metadata
+/// is not included; [`RemoteStorage::head_object`] returns it separately.
+pub struct Download {
+    pub download_stream: Pin<Box<dyn AsyncRead + Unpin + Send + Sync>>,
+}
+
+impl Debug for Download {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Download").finish_non_exhaustive()
+    }
+}
+
+/// Metadata about a single remote object, as returned by [`RemoteStorage::head_object`]
+/// and by the `objects` of a [`ListResult`] page.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjectMeta {
+    pub path: RemotePath,
+    pub size: u64,
+    pub last_modified: std::time::SystemTime,
+    pub etag: String,
+}
+
+/// One page of a [`RemoteStorage::list_streaming`] listing, corresponding to exactly one
+/// underlying HTTP response from the backend.
+#[derive(Debug, Clone, Default)]
+pub struct ListResult {
+    pub objects: Vec<ObjectMeta>,
+    pub common_prefixes: Vec<RemotePath>,
+}
+
+/// Controls whether [`RemoteStorage::list_streaming`] groups keys under their first
+/// path segment (returning them as `common_prefixes`, like a directory listing) or
+/// returns every matching object individually, regardless of nesting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListingMode {
+    WithDelimiter,
+    NoDelimiter,
+}
+
+/// A precondition that [`RemoteStorage::upload`] must satisfy before writing, giving
+/// callers a cheap compare-and-swap primitive without a separate locking mechanism.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UploadPrecondition {
+    /// `If-None-Match: *` -- the upload fails with [`PreconditionError`] if an object
+    /// already exists at the destination path, giving create-only semantics.
+    CreateOnly,
+}
+
+/// A precondition that [`RemoteStorage::download`] / [`RemoteStorage::download_byte_range`]
+/// must satisfy before returning data, letting callers detect concurrent modification of
+/// an object they previously read the ETag of.
+#[derive(Debug, Clone)]
+pub enum DownloadPrecondition {
+    /// `If-Match: <etag>` -- the download fails with [`PreconditionError`] if the
+    /// object's current ETag no longer matches.
+    IfMatch(String),
+}
+
+/// Returned by [`RemoteStorage::upload`] / [`RemoteStorage::download`] /
+/// [`RemoteStorage::download_byte_range`] when the caller supplied a precondition
+/// (`If-Match` / `If-None-Match`) that the object's current state on the backend didn't
+/// satisfy. Carried inside the `anyhow::Error` returned by those methods; callers that
+/// care about distinguishing this from other failures can recover it with
+/// `err.downcast_ref::<PreconditionError>()` or `err.is::<PreconditionError>()`.
+#[derive(Debug, Clone)]
+pub struct PreconditionError {
+    pub path: RemotePath,
+}
+
+impl std::fmt::Display for PreconditionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "precondition failed for {}", self.path)
+    }
+}
+
+impl std::error::Error for PreconditionError {}
+
+/// A boxed, page-at-a-time listing stream, as returned by [`RemoteStorage::list_streaming`].
+pub type BoxStream<'a, T> = Pin<Box<dyn futures::Stream<Item = T> + Send + 'a>>;
+
+/// Top-level configuration for whichever remote storage backend is in use.
+#[derive(Debug, Clone)]
+pub struct RemoteStorageConfig {
+    /// Max number of concurrent sync (upload/download/delete) operations the caller
+    /// intends to run against this storage at once. Not passed to the backend:
+    /// [`GenericRemoteStorage::from_config`] only reads `storage`, and the Azure backend
+    /// takes its limit from [`AzureConfig::concurrency_limit`].
+    pub max_concurrent_syncs: NonZeroUsize,
+    /// Max number of consecutive sync errors to tolerate before the caller should give up.
+    pub max_sync_errors: NonZeroU32,
+    pub storage: RemoteStorageKind,
+}
+
+/// Which backend `storage` in [`RemoteStorageConfig`] refers to, and its backend-specific
+/// configuration.
+#[derive(Debug, Clone)]
+pub enum RemoteStorageKind {
+    AzureContainer(AzureConfig),
+}
+
+/// Configuration for the Azure Blob Storage backend.
+#[derive(Debug, Clone)]
+pub struct AzureConfig {
+    pub container_name: String,
+    pub container_region: String,
+    pub prefix_in_container: Option<String>,
+    /// Bounds how many blocks [`RemoteStorage::upload_multipart`] stages at once and how
+    /// many deletes [`RemoteStorage::delete_objects`] runs at once. Other operations are
+    /// not limited by it.
+    pub concurrency_limit: NonZeroUsize,
+    pub max_keys_per_list_response: Option<i32>,
+    /// Size of each block [`RemoteStorage::upload_multipart`] stages before committing
+    /// the block list. Defaults to 8 MiB when unset.
+    pub multipart_chunk_size: Option<NonZeroUsize>,
+    /// Overrides the blob service endpoint the client connects to. When unset, the
+    /// client talks to `https://{account}.blob.core.windows.net` as usual; when set,
+    /// it's pointed at this URL instead (e.g. `http://127.0.0.1:10000/devstoreaccount1`
+    /// for a local Azurite emulator), so the same client code path can be exercised in
+    /// tests and local development without a real Azure container.
+    pub endpoint: Option<String>,
+    /// Which credential the client authenticates with. Defaults to
+    /// [`AzureAuthMethod::AccountKey`] -- most existing configs don't set this.
+    pub auth: AzureAuthMethod,
+}
+
+/// Which credential [`azure_blob::AzureBlobStorage`] authenticates to Azure Blob Storage
+/// with. Long-lived account keys are convenient for local dev but disallowed in most
+/// production deployments, so production configs want one of the other two variants
+/// instead.
+#[derive(Debug, Clone, Default)]
+pub enum AzureAuthMethod {
+    /// A storage account key read from the `AZURE_STORAGE_ACCESS_KEY` env var (or,
+    /// against the Azurite emulator, the well-known devstorage key).
+    #[default]
+    AccountKey,
+    /// A pre-generated shared-access-signature token, scoped to whatever
+    /// resource/permissions/expiry it was minted with.
+    SasToken(String),
+    /// A managed or workload identity: the credential is fetched from the instance
+    /// metadata (Azure VM) or OIDC (workload identity / AKS) endpoint and refreshed
+    /// automatically as it nears expiry, the same credential chain `azure_identity`
+    /// wires into other `object_store`-style clients.
+    ManagedIdentity,
+}
+
+/// Common interface every remote storage backend implements.
+///
+/// Readers/writers are generic rather than `Box<dyn ...>` so that callers avoid an
+/// extra allocation and vtable indirection on the hot upload/download path; this is why
+/// `GenericRemoteStorage` below dispatches by matching on an enum instead of holding a
+/// `Box<dyn RemoteStorage>` -- a trait with a generic method isn't object-safe.
+#[async_trait::async_trait]
+pub trait RemoteStorage: Send + Sync + 'static {
+    /// List keys under `prefix`, one page (one underlying HTTP response) at a time, so
+    /// peak memory is bounded by a single page rather than by the total object count.
+    /// `list_prefixes`/`list_files` below are just thin collectors over this.
+    fn list_streaming<'a>(
+        &'a self,
+        mode: ListingMode,
+        prefix: Option<&'a RemotePath>,
+    ) -> BoxStream<'a, Result<ListResult>>;
+
+    async fn list_prefixes(&self, prefix: Option<&RemotePath>) -> Result<Vec<RemotePath>> {
+        let mut prefixes = Vec::new();
+        let mut stream = self.list_streaming(ListingMode::WithDelimiter, prefix);
+        while let Some(page) = futures::StreamExt::next(&mut stream).await {
+            prefixes.extend(page?.common_prefixes);
+        }
+        Ok(prefixes)
+    }
+
+    /// List every object under `prefix` (the configured root when `None`) at any depth:
+    /// no delimiter is used, so nested keys are returned one by one instead of being
+    /// grouped into prefixes as [`Self::list_prefixes`] does. All pages are collected
+    /// into one `Vec`.
+    async fn list_files(&self, prefix: Option<&RemotePath>) -> Result<Vec<ObjectMeta>> {
+        let mut files = Vec::new();
+        let mut stream = self.list_streaming(ListingMode::NoDelimiter, prefix);
+        while let Some(page) = futures::StreamExt::next(&mut stream).await {
+            files.extend(page?.objects);
+        }
+        Ok(files)
+    }
+
+    /// Fetch metadata for a single object without downloading its body. Cheaper than a
+    /// full `download` when the caller only needs to check existence, size, or ETag
+    /// (e.g. to decide whether a local copy is already up to date).
+    async fn head_object(&self, path: &RemotePath) -> Result<ObjectMeta>;
+
+    /// `precondition` is `None` for a plain overwriting upload; pass
+    /// `Some(UploadPrecondition::CreateOnly)` for create-only (compare-and-swap) semantics.
+    async fn upload(
+        &self,
+        from: impl AsyncRead + Unpin + Send + Sync + 'static,
+        from_size_bytes: usize,
+        to: &RemotePath,
+        metadata: Option<StorageMetadata>,
+        precondition: Option<UploadPrecondition>,
+    ) -> Result<()>;
+
+    /// `precondition` is `None` for a plain download; pass
+    /// `Some(DownloadPrecondition::IfMatch(etag))` to fail instead of returning stale
+    /// data if the object changed since the caller last read its ETag.
+    async fn download(
+        &self,
+        from: &RemotePath,
+        precondition: Option<DownloadPrecondition>,
+    ) -> Result<Download> {
+        self.download_byte_range(from, 0, None, precondition).await
+    }
+
+    async fn download_byte_range(
+        &self,
+        from: &RemotePath,
+        start_inclusive: u64,
+        end_exclusive: Option<u64>,
+        precondition: Option<DownloadPrecondition>,
+    ) -> Result<Download>;
+
+    /// Upload `from` without requiring the caller to know its length up front, splitting
+    /// it into fixed-size blocks staged with bounded concurrency and committed as one
+    /// object once every block has landed. Falls back to a plain single-PUT [`Self::upload`]
+    /// when `from` turns out to be smaller than one block.
+    async fn upload_multipart(
+        &self,
+        from: impl AsyncRead + Unpin + Send + Sync + 'static,
+        to: &RemotePath,
+        metadata: Option<StorageMetadata>,
+    ) -> Result<()>;
+
+    async fn delete(&self, path: &RemotePath) -> Result<()>;
+
+    async fn delete_objects(&self, paths: &[RemotePath]) -> Result<()>;
+}
+
+/// A remote storage client for whichever backend was selected in the config passed to
+/// [`GenericRemoteStorage::from_config`].
+pub enum GenericRemoteStorage {
+    AzureBlob(AzureBlobStorage),
+}
+
+impl GenericRemoteStorage {
+    pub fn from_config(config: &RemoteStorageConfig) -> Result<Self> {
+        Ok(match &config.storage {
+            RemoteStorageKind::AzureContainer(azure_config) => {
+                GenericRemoteStorage::AzureBlob(AzureBlobStorage::new(azure_config)?)
+            }
+        })
+    }
+
+    pub fn list_streaming<'a>(
+        &'a self,
+        mode: ListingMode,
+        prefix: Option<&'a RemotePath>,
+    ) -> BoxStream<'a, Result<ListResult>> {
+        match self {
+            Self::AzureBlob(s) => s.list_streaming(mode, prefix),
+        }
+    }
+
+    pub async fn list_prefixes(&self, prefix: Option<&RemotePath>) -> Result<Vec<RemotePath>> {
+        match self {
+            Self::AzureBlob(s) => s.list_prefixes(prefix).await,
+        }
+    }
+
+    pub async fn list_files(&self, prefix: Option<&RemotePath>) -> Result<Vec<ObjectMeta>> {
+        match self {
+            Self::AzureBlob(s) => s.list_files(prefix).await,
+        }
+    }
+
+    pub async fn head_object(&self, path: &RemotePath) -> Result<ObjectMeta> {
+        match self {
+            Self::AzureBlob(s) => s.head_object(path).await,
+        }
+    }
+
+    pub async fn upload(
+        &self,
+        from: impl AsyncRead + Unpin + Send + Sync + 'static,
+        from_size_bytes: usize,
+        to: &RemotePath,
+        metadata: Option<StorageMetadata>,
+        precondition: Option<UploadPrecondition>,
+    ) -> Result<()> {
+        match self {
+            Self::AzureBlob(s) => {
+                s.upload(from, from_size_bytes, to, metadata, precondition)
+                    .await
+            }
+        }
+    }
+
+    pub async fn download(
+        &self,
+        from: &RemotePath,
+        precondition: Option<DownloadPrecondition>,
+    ) -> Result<Download> {
+        match self {
+            Self::AzureBlob(s) => s.download(from, precondition).await,
+        }
+    }
+
+    pub async fn download_byte_range(
+        &self,
+        from: &RemotePath,
+        start_inclusive: u64,
+        end_exclusive: Option<u64>,
+        precondition: Option<DownloadPrecondition>,
+    ) -> Result<Download> {
+        match self {
+            Self::AzureBlob(s) => {
+                s.download_byte_range(from, start_inclusive, end_exclusive, precondition)
+                    .await
+            }
+        }
+    }
+
+    pub async fn upload_multipart(
+        &self,
+        from: impl AsyncRead + Unpin + Send + Sync + 'static,
+        to: &RemotePath,
+        metadata: Option<StorageMetadata>,
+    ) -> Result<()> {
+        match self {
+            Self::AzureBlob(s) => s.upload_multipart(from, to, metadata).await,
+        }
+    }
+
+    pub async fn delete(&self, path: &RemotePath) -> Result<()> {
+        match self {
+            Self::AzureBlob(s) => s.delete(path).await,
+        }
+    }
+
+    pub async fn delete_objects(&self, paths: &[RemotePath]) -> Result<()> {
+        match self {
+            Self::AzureBlob(s) => s.delete_objects(paths).await,
+        }
+    }
+}
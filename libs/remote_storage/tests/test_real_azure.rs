@@ -10,7 +10,8 @@ use anyhow::Context;
 use camino::Utf8Path;
 use once_cell::sync::OnceCell;
 use remote_storage::{
-    AzureConfig, Download, GenericRemoteStorage, RemotePath, RemoteStorageConfig, RemoteStorageKind,
+    AzureAuthMethod, AzureConfig, Download, DownloadPrecondition, GenericRemoteStorage,
+    PreconditionError, RemotePath, RemoteStorageConfig, RemoteStorageKind, UploadPrecondition,
 };
 use test_context::{test_context, AsyncTestContext};
 use tokio::task::JoinSet;
@@ -22,6 +23,10 @@ const ENABLE_REAL_AZURE_REMOTE_STORAGE_ENV_VAR_NAME: &str = "ENABLE_REAL_AZURE_R
 
 const BASE_PREFIX: &str = "test";
 
+/// `multipart_chunk_size` used by [`MaybeEnabledAzureSmallChunks`], small enough that
+/// ordinary test-sized payloads span several blocks.
+const SMALL_CHUNK_SIZE: usize = 1024;
+
 /// Tests that the Azure client can list all prefixes, even if the response comes paginated and requires multiple HTTP queries.
 /// Uses real Azure and requires [`ENABLE_REAL_AZURE_REMOTE_STORAGE_ENV_VAR_NAME`] and related Azure cred env vars specified.
 /// See the client creation in [`create_azure_client`] for details on the required env vars.
@@ -119,6 +124,7 @@ async fn azure_list_files_works(
         .await
         .context("client list root files failure")?
         .into_iter()
+        .map(|meta| meta.path)
         .collect::<HashSet<_>>();
     assert_eq!(
         root_files,
@@ -130,6 +136,7 @@ async fn azure_list_files_works(
         .await
         .context("client list nested files failure")?
         .into_iter()
+        .map(|meta| meta.path)
         .collect::<HashSet<_>>();
     let trim_remote_blobs: HashSet<_> = ctx
         .remote_blobs
@@ -187,15 +194,15 @@ async fn azure_delete_objects_works(ctx: &mut MaybeEnabledAzure) -> anyhow::Resu
     let data3 = "remote blob data3".as_bytes();
     let data3_len = data3.len();
     ctx.client
-        .upload(std::io::Cursor::new(data1), data1_len, &path1, None)
+        .upload(std::io::Cursor::new(data1), data1_len, &path1, None, None)
         .await?;
 
     ctx.client
-        .upload(std::io::Cursor::new(data2), data2_len, &path2, None)
+        .upload(std::io::Cursor::new(data2), data2_len, &path2, None, None)
         .await?;
 
     ctx.client
-        .upload(std::io::Cursor::new(data3), data3_len, &path3, None)
+        .upload(std::io::Cursor::new(data3), data3_len, &path3, None, None)
         .await?;
 
     ctx.client.delete_objects(&[path1, path2]).await?;
@@ -223,50 +230,175 @@ async fn azure_upload_download_works(ctx: &mut MaybeEnabledAzure) -> anyhow::Res
     let data_len = data.len() as u64;
 
     ctx.client
-        .upload(std::io::Cursor::new(data), data.len(), &path, None)
+        .upload(std::io::Cursor::new(data), data.len(), &path, None, None)
         .await?;
 
+    let meta = ctx.client.head_object(&path).await?;
+    assert_eq!(meta.path, path);
+    assert_eq!(meta.size, data_len);
+
     async fn download_and_compare(mut dl: Download) -> anyhow::Result<Vec<u8>> {
         let mut buf = Vec::new();
         tokio::io::copy(&mut dl.download_stream, &mut buf).await?;
         Ok(buf)
     }
     // Normal download request
-    let dl = ctx.client.download(&path).await?;
+    let dl = ctx.client.download(&path, None).await?;
+    let buf = download_and_compare(dl).await?;
+    assert_eq!(buf, data);
+
+    // Conditional download with the current ETag: should succeed.
+    let dl = ctx
+        .client
+        .download(&path, Some(DownloadPrecondition::IfMatch(meta.etag.clone())))
+        .await?;
     let buf = download_and_compare(dl).await?;
     assert_eq!(buf, data);
 
+    // Conditional download with a stale ETag: should fail with a precondition error.
+    let stale_etag_err = ctx
+        .client
+        .download(
+            &path,
+            Some(DownloadPrecondition::IfMatch("\"not-the-real-etag\"".to_string())),
+        )
+        .await
+        .expect_err("download with a stale If-Match should fail");
+    assert!(stale_etag_err.is::<PreconditionError>());
+
     // Full range (end specified)
     let dl = ctx
         .client
-        .download_byte_range(&path, 0, Some(data_len))
+        .download_byte_range(&path, 0, Some(data_len), None)
         .await?;
     let buf = download_and_compare(dl).await?;
     assert_eq!(buf, data);
 
     // partial range (end specified)
-    let dl = ctx.client.download_byte_range(&path, 4, Some(10)).await?;
+    let dl = ctx
+        .client
+        .download_byte_range(&path, 4, Some(10), None)
+        .await?;
     let buf = download_and_compare(dl).await?;
     assert_eq!(buf, data[4..10]);
 
     // partial range (end beyond real end)
     let dl = ctx
         .client
-        .download_byte_range(&path, 8, Some(data_len * 100))
+        .download_byte_range(&path, 8, Some(data_len * 100), None)
         .await?;
     let buf = download_and_compare(dl).await?;
     assert_eq!(buf, data[8..]);
 
     // Partial range (end unspecified)
-    let dl = ctx.client.download_byte_range(&path, 4, None).await?;
+    let dl = ctx
+        .client
+        .download_byte_range(&path, 4, None, None)
+        .await?;
     let buf = download_and_compare(dl).await?;
     assert_eq!(buf, data[4..]);
 
     // Full range (end unspecified)
-    let dl = ctx.client.download_byte_range(&path, 0, None).await?;
+    let dl = ctx
+        .client
+        .download_byte_range(&path, 0, None, None)
+        .await?;
     let buf = download_and_compare(dl).await?;
     assert_eq!(buf, data);
 
+    // Re-uploading with `If-None-Match: *` should fail now that the blob exists,
+    // giving the pageserver a create-only compare-and-swap primitive.
+    let reupload_err = ctx
+        .client
+        .upload(
+            std::io::Cursor::new(data),
+            data.len(),
+            &path,
+            None,
+            Some(UploadPrecondition::CreateOnly),
+        )
+        .await
+        .expect_err("conditional re-upload of an existing blob should fail");
+    assert!(reupload_err.is::<PreconditionError>());
+
+    debug!("Cleanup: deleting file at path {path:?}");
+    ctx.client
+        .delete(&path)
+        .await
+        .with_context(|| format!("{path:?} removal"))?;
+
+    Ok(())
+}
+
+/// `upload_multipart` doesn't need the caller to know the length up front; this
+/// exercises the common case of an input that fits in a single chunk, which falls back
+/// to a plain single-PUT `upload` internally.
+#[test_context(MaybeEnabledAzure)]
+#[tokio::test]
+async fn azure_upload_multipart_works(ctx: &mut MaybeEnabledAzure) -> anyhow::Result<()> {
+    let MaybeEnabledAzure::Enabled(ctx) = ctx else {
+        return Ok(());
+    };
+
+    let path = RemotePath::new(Utf8Path::new(
+        format!("{}/multipart_file", ctx.base_prefix).as_str(),
+    ))
+    .with_context(|| "RemotePath conversion")?;
+
+    let data = "remote blob data uploaded without a known length".as_bytes();
+
+    ctx.client
+        .upload_multipart(std::io::Cursor::new(data), &path, None)
+        .await?;
+
+    let mut dl = ctx.client.download(&path, None).await?;
+    let mut buf = Vec::new();
+    tokio::io::copy(&mut dl.download_stream, &mut buf).await?;
+    assert_eq!(buf, data);
+
+    debug!("Cleanup: deleting file at path {path:?}");
+    ctx.client
+        .delete(&path)
+        .await
+        .with_context(|| format!("{path:?} removal"))?;
+
+    Ok(())
+}
+
+/// Exercises the actual multi-block path of `upload_multipart` (staging several
+/// uncommitted blocks with bounded concurrency, then committing the block list), as
+/// opposed to [`azure_upload_multipart_works`], which only ever takes the single-PUT
+/// fallback. Uses a client configured with a small `multipart_chunk_size` so ordinary
+/// test-sized data spans several blocks.
+#[test_context(MaybeEnabledAzureSmallChunks)]
+#[tokio::test]
+async fn azure_upload_multipart_multi_block_works(
+    ctx: &mut MaybeEnabledAzureSmallChunks,
+) -> anyhow::Result<()> {
+    let MaybeEnabledAzureSmallChunks::Enabled(ctx) = ctx else {
+        return Ok(());
+    };
+
+    let path = RemotePath::new(Utf8Path::new(
+        format!("{}/multipart_multi_block_file", ctx.base_prefix).as_str(),
+    ))
+    .with_context(|| "RemotePath conversion")?;
+
+    // `SMALL_CHUNK_SIZE` blocks, the last one partial, so staging exercises more than
+    // one block and the block list commit has to stitch them back together in order.
+    let data: Vec<u8> = (0..SMALL_CHUNK_SIZE * 3 + SMALL_CHUNK_SIZE / 2)
+        .map(|i| (i % 256) as u8)
+        .collect();
+
+    ctx.client
+        .upload_multipart(std::io::Cursor::new(data.clone()), &path, None)
+        .await?;
+
+    let mut dl = ctx.client.download(&path, None).await?;
+    let mut buf = Vec::new();
+    tokio::io::copy(&mut dl.download_stream, &mut buf).await?;
+    assert_eq!(buf, data);
+
     debug!("Cleanup: deleting file at path {path:?}");
     ctx.client
         .delete(&path)
@@ -292,8 +424,11 @@ struct EnabledAzure {
 }
 
 impl EnabledAzure {
-    async fn setup(max_keys_in_list_response: Option<i32>) -> Self {
-        let client = create_azure_client(max_keys_in_list_response)
+    async fn setup(
+        max_keys_in_list_response: Option<i32>,
+        multipart_chunk_size: Option<NonZeroUsize>,
+    ) -> Self {
+        let client = create_azure_client(max_keys_in_list_response, multipart_chunk_size)
             .context("Azure client creation")
             .expect("Azure client creation failed");
 
@@ -322,7 +457,32 @@ impl AsyncTestContext for MaybeEnabledAzure {
             return Self::Disabled;
         }
 
-        Self::Enabled(EnabledAzure::setup(None).await)
+        Self::Enabled(EnabledAzure::setup(None, None).await)
+    }
+}
+
+/// Same as [`MaybeEnabledAzure`], but configured with a small `multipart_chunk_size`
+/// so that `upload_multipart` takes its real multi-block path on ordinary test-sized
+/// payloads instead of always falling back to a single PUT.
+enum MaybeEnabledAzureSmallChunks {
+    Enabled(EnabledAzure),
+    Disabled,
+}
+
+#[async_trait::async_trait]
+impl AsyncTestContext for MaybeEnabledAzureSmallChunks {
+    async fn setup() -> Self {
+        ensure_logging_ready();
+
+        if env::var(ENABLE_REAL_AZURE_REMOTE_STORAGE_ENV_VAR_NAME).is_err() {
+            info!(
+                "`{}` env variable is not set, skipping the test",
+                ENABLE_REAL_AZURE_REMOTE_STORAGE_ENV_VAR_NAME
+            );
+            return Self::Disabled;
+        }
+
+        Self::Enabled(EnabledAzure::setup(None, NonZeroUsize::new(SMALL_CHUNK_SIZE)).await)
     }
 }
 
@@ -353,7 +513,7 @@ impl AsyncTestContext for MaybeEnabledAzureWithTestBlobs {
         let max_keys_in_list_response = 10;
         let upload_tasks_count = 1 + (2 * usize::try_from(max_keys_in_list_response).unwrap());
 
-        let enabled = EnabledAzure::setup(Some(max_keys_in_list_response)).await;
+        let enabled = EnabledAzure::setup(Some(max_keys_in_list_response), None).await;
 
         match upload_azure_data(&enabled.client, enabled.base_prefix, upload_tasks_count).await {
             ControlFlow::Continue(uploads) => {
@@ -415,7 +575,7 @@ impl AsyncTestContext for MaybeEnabledAzureWithSimpleTestBlobs {
         let max_keys_in_list_response = 10;
         let upload_tasks_count = 1 + (2 * usize::try_from(max_keys_in_list_response).unwrap());
 
-        let enabled = EnabledAzure::setup(Some(max_keys_in_list_response)).await;
+        let enabled = EnabledAzure::setup(Some(max_keys_in_list_response), None).await;
 
         match upload_simple_azure_data(&enabled.client, upload_tasks_count).await {
             ControlFlow::Continue(uploads) => {
@@ -448,6 +608,7 @@ impl AsyncTestContext for MaybeEnabledAzureWithSimpleTestBlobs {
 
 fn create_azure_client(
     max_keys_per_list_response: Option<i32>,
+    multipart_chunk_size: Option<NonZeroUsize>,
 ) -> anyhow::Result<Arc<GenericRemoteStorage>> {
     use rand::Rng;
 
@@ -477,6 +638,9 @@ fn create_azure_client(
             prefix_in_container: Some(format!("test_{millis}_{random:08x}/")),
             concurrency_limit: NonZeroUsize::new(100).unwrap(),
             max_keys_per_list_response,
+            multipart_chunk_size,
+            endpoint: env::var("AZURE_STORAGE_ENDPOINT").ok(),
+            auth: AzureAuthMethod::default(),
         }),
     };
     Ok(Arc::new(
@@ -508,7 +672,7 @@ async fn upload_azure_data(
             let data = format!("remote blob data {i}").into_bytes();
             let data_len = data.len();
             task_client
-                .upload(std::io::Cursor::new(data), data_len, &blob_path, None)
+                .upload(std::io::Cursor::new(data), data_len, &blob_path, None, None)
                 .await?;
 
             Ok::<_, anyhow::Error>((blob_prefix, blob_path))
@@ -593,7 +757,7 @@ async fn upload_simple_azure_data(
             let data = format!("remote blob data {i}").into_bytes();
             let data_len = data.len();
             task_client
-                .upload(std::io::Cursor::new(data), data_len, &blob_path, None)
+                .upload(std::io::Cursor::new(data), data_len, &blob_path, None, None)
                 .await?;
 
             Ok::<_, anyhow::Error>(blob_path)
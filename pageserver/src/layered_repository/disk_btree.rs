@@ -0,0 +1,393 @@
+//! An on-disk, block-resident B-tree.
+//!
+//! This is used by the image layer index instead of a serialized `HashMap`.
+//! A `HashMap` index has to be fully deserialized into memory before the
+//! first lookup can happen, and kept there for as long as the layer stays
+//! loaded. A disk B-tree only ever touches one block per level for a given
+//! lookup, via [`FileBlockReader`], so memory use is bounded by the buffer
+//! cache instead of by the number of keys in the layer.
+//!
+//! Both leaf and internal nodes are stored one per disk block:
+//! * A leaf block holds a sorted run of `(key, value)` pairs.
+//! * An internal block holds a sorted run of `(key, child block number)`
+//!   fence pointers: the child reachable through a given entry contains all
+//!   keys `>= entry.0` and `< ` the next entry's key.
+//!
+//! [`DiskBtreeBuilder::append`] must be called with keys in ascending
+//! order, which is how `ImageLayerWriter::put_image` already receives them
+//! (blknum order); this lets the tree be built bottom-up in a single pass.
+//! The builder keeps every appended entry in memory until
+//! [`DiskBtreeBuilder::finish`] writes the tree out.
+
+use crate::layered_repository::block_io::FileBlockReader;
+use crate::page_cache::PAGE_SZ;
+use crate::virtual_file::VirtualFile;
+use anyhow::{ensure, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io::{Seek, SeekFrom, Write};
+
+use zenith_utils::bin_ser::BeSer;
+
+/// Tag byte written at the start of every block, identifying it as a leaf or
+/// an internal (fence-pointer) node.
+const LEAF_TAG: u8 = 0;
+const INTERNAL_TAG: u8 = 1;
+
+/// Leave some headroom below `PAGE_SZ` for the tag byte and serialization
+/// overhead, so a slightly pessimistic per-entry size estimate never
+/// actually causes a block to overflow.
+const NODE_BUDGET: usize = PAGE_SZ - 32;
+
+/// Builds a [`DiskBtreeReader`]-compatible tree on disk, bottom-up, from a
+/// stream of keys appended in ascending order.
+pub struct DiskBtreeBuilder<K, V> {
+    leaf_entries: Vec<(K, V)>,
+}
+
+impl<K, V> Default for DiskBtreeBuilder<K, V> {
+    fn default() -> Self {
+        DiskBtreeBuilder {
+            leaf_entries: Vec::new(),
+        }
+    }
+}
+
+impl<K, V> DiskBtreeBuilder<K, V>
+where
+    K: Ord + Clone + Serialize + DeserializeOwned,
+    V: Copy + Serialize + DeserializeOwned,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append the next key and its value. Keys must be appended in strictly
+    /// ascending order: a leaf block's entries are written out sorted but
+    /// never re-sorted, so an out-of-order or duplicate key would make
+    /// `DiskBtreeReader`'s binary search silently return wrong or missing
+    /// entries instead of failing loudly, which is why this is a real,
+    /// release-mode check rather than a `debug_assert!`.
+    pub fn append(&mut self, key: K, value: V) -> Result<()> {
+        ensure!(
+            self.leaf_entries.last().map_or(true, |(last, _)| *last < key),
+            "DiskBtreeBuilder::append called out of order"
+        );
+        self.leaf_entries.push((key, value));
+        Ok(())
+    }
+
+    /// Write the tree to `writer`, starting at block `start_blk`.
+    ///
+    /// Returns `(root_blk, next_free_blk)`: the block number of the root
+    /// node (to be recorded in the layer's summary), and the first unused
+    /// block number after the end of the tree.
+    pub fn finish<W: Write + Seek>(self, writer: &mut W, start_blk: u32) -> Result<(u32, u32)> {
+        let mut next_blk = start_blk;
+
+        // Write out the leaf level, and remember a fence pointer (first key,
+        // block number) for each leaf block we wrote.
+        let mut level: Vec<(K, u32)> = Vec::new();
+        for batch in batch_by_size(self.leaf_entries) {
+            let first_key = batch[0].0.clone();
+            write_node(writer, next_blk, LEAF_TAG, &batch)?;
+            level.push((first_key, next_blk));
+            next_blk += 1;
+        }
+
+        // An empty layer still needs a root leaf for lookups to land on.
+        if level.is_empty() {
+            write_node::<_, K, V>(writer, next_blk, LEAF_TAG, &[])?;
+            return Ok((next_blk, next_blk + 1));
+        }
+
+        // Keep stacking fence-pointer levels on top until only the root is left.
+        while level.len() > 1 {
+            let mut next_level = Vec::new();
+            for batch in batch_by_size(level) {
+                let first_key = batch[0].0.clone();
+                write_node(writer, next_blk, INTERNAL_TAG, &batch)?;
+                next_level.push((first_key, next_blk));
+                next_blk += 1;
+            }
+            level = next_level;
+        }
+
+        let root_blk = level[0].1;
+        Ok((root_blk, next_blk))
+    }
+}
+
+fn write_node<W, K, V>(writer: &mut W, blk: u32, tag: u8, entries: &[(K, V)]) -> Result<()>
+where
+    W: Write + Seek,
+    K: Clone + Serialize + DeserializeOwned,
+    V: Clone + Serialize + DeserializeOwned,
+{
+    let mut buf = Vec::with_capacity(PAGE_SZ);
+    buf.push(tag);
+    buf.extend_from_slice(&entries.to_vec().ser()?);
+    anyhow::ensure!(
+        buf.len() <= PAGE_SZ,
+        "disk B-tree node overflowed a {}-byte block ({} bytes)",
+        PAGE_SZ,
+        buf.len()
+    );
+    writer.seek(SeekFrom::Start(blk as u64 * PAGE_SZ as u64))?;
+    writer.write_all(&buf)?;
+    Ok(())
+}
+
+/// Greedily pack entries into batches that are each expected to fit in one
+/// block. Keys and values are ordinary serde types here, not a fixed-width
+/// layout, so the exact size of a batch isn't known until it's serialized;
+/// probe each entry's own serialized size as a (slightly pessimistic due to
+/// `NODE_BUDGET`) proxy for its contribution to the batch.
+fn batch_by_size<K, V>(entries: Vec<(K, V)>) -> Vec<Vec<(K, V)>>
+where
+    K: Serialize + DeserializeOwned,
+    V: Serialize + DeserializeOwned,
+{
+    let mut batches = Vec::new();
+    let mut cur = Vec::new();
+    let mut cur_size = 0usize;
+    for entry in entries {
+        let entry_size = entry.ser().map(|b| b.len()).unwrap_or(64);
+        if !cur.is_empty() && cur_size + entry_size > NODE_BUDGET {
+            batches.push(std::mem::take(&mut cur));
+            cur_size = 0;
+        }
+        cur_size += entry_size;
+        cur.push(entry);
+    }
+    if !cur.is_empty() {
+        batches.push(cur);
+    }
+    batches
+}
+
+/// Reads a tree written by [`DiskBtreeBuilder`], probing only the blocks on
+/// the path to the requested key.
+pub struct DiskBtreeReader<K, V> {
+    root_blk: u32,
+    _phantom: std::marker::PhantomData<(K, V)>,
+}
+
+impl<K, V> DiskBtreeReader<K, V>
+where
+    K: Ord + Clone + Serialize + DeserializeOwned,
+    V: Copy + Serialize + DeserializeOwned,
+{
+    pub fn new(root_blk: u32) -> Self {
+        DiskBtreeReader {
+            root_blk,
+            _phantom: std::marker::PhantomData,
+        }
+    }
+
+    /// Read every `(key, value)` pair in the tree, in ascending key order.
+    ///
+    /// Unlike [`Self::get`], this visits every block of the tree rather than just the
+    /// ones on a single lookup path, so it's only meant for bulk operations -- dumping
+    /// a layer for debugging, or the background scrubber verifying every blob -- not
+    /// the read path.
+    pub fn iter_all(&self, file: &FileBlockReader<VirtualFile>) -> Result<Vec<(K, V)>> {
+        let mut out = Vec::new();
+        self.visit(file, self.root_blk, &mut out)?;
+        Ok(out)
+    }
+
+    fn visit(
+        &self,
+        file: &FileBlockReader<VirtualFile>,
+        blk: u32,
+        out: &mut Vec<(K, V)>,
+    ) -> Result<()> {
+        let block = file.read_blk(blk)?;
+        let buf = block.as_ref();
+        anyhow::ensure!(!buf.is_empty(), "disk B-tree block {blk} is empty");
+        match buf[0] {
+            LEAF_TAG => {
+                let entries: Vec<(K, V)> = Vec::des_prefix(&buf[1..])?;
+                out.extend(entries);
+            }
+            INTERNAL_TAG => {
+                let entries: Vec<(K, u32)> = Vec::des_prefix(&buf[1..])?;
+                for (_, child_blk) in entries {
+                    self.visit(file, child_blk, out)?;
+                }
+            }
+            other => anyhow::bail!("disk B-tree block {blk} has unknown tag {other}"),
+        }
+        Ok(())
+    }
+
+    /// Look up `key`, reading one block per tree level through `file`.
+    pub fn get(&self, file: &FileBlockReader<VirtualFile>, key: &K) -> Result<Option<V>> {
+        let mut blk = self.root_blk;
+        loop {
+            let block = file.read_blk(blk)?;
+            let buf = block.as_ref();
+            anyhow::ensure!(!buf.is_empty(), "disk B-tree block {blk} is empty");
+            let tag = buf[0];
+            match tag {
+                LEAF_TAG => {
+                    let entries: Vec<(K, V)> = Vec::des_prefix(&buf[1..])?;
+                    return Ok(floor_entry(&entries, key).and_then(|(k, v)| {
+                        if k == key {
+                            Some(*v)
+                        } else {
+                            None
+                        }
+                    }));
+                }
+                INTERNAL_TAG => {
+                    let entries: Vec<(K, u32)> = Vec::des_prefix(&buf[1..])?;
+                    match floor_entry(&entries, key) {
+                        Some((_, child_blk)) => blk = *child_blk,
+                        // Key is smaller than every entry in this layer's tree: not present.
+                        None => return Ok(None),
+                    }
+                }
+                other => anyhow::bail!("disk B-tree block {blk} has unknown tag {other}"),
+            }
+        }
+    }
+}
+
+/// Binary search for the last entry whose key is `<= key` (the entry whose
+/// subtree, or exact value, `key` would fall under).
+fn floor_entry<'a, K: Ord, V>(entries: &'a [(K, V)], key: &K) -> Option<&'a (K, V)> {
+    let idx = entries.partition_point(|(k, _)| k <= key);
+    if idx == 0 {
+        None
+    } else {
+        Some(&entries[idx - 1])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Read block `blk` out of a buffer built by [`DiskBtreeBuilder::finish`] and
+    /// return its tag byte and payload. Stands in for [`FileBlockReader`], which
+    /// `DiskBtreeReader::get`/`iter_all` are hard-wired to read through a real
+    /// [`VirtualFile`] -- not available to a plain in-memory test -- so these tests
+    /// walk the written buffer directly instead, using the same tag/entries layout
+    /// [`DiskBtreeReader::visit`] does.
+    fn read_block(buf: &[u8], blk: u32) -> (u8, &[u8]) {
+        let start = blk as usize * PAGE_SZ;
+        // Blocks are written back-to-back in ascending order with no overwrites, so
+        // everything from `start` onward (this block's content, then zero padding up
+        // to the next block's start, then later blocks) is available; `Vec::des_prefix`
+        // stops once it has read the length-prefixed entries it needs, so the extra
+        // trailing bytes are harmless.
+        (buf[start], &buf[start + 1..])
+    }
+
+    fn collect_all(buf: &[u8], blk: u32, out: &mut Vec<(i64, u64)>) {
+        let (tag, payload) = read_block(buf, blk);
+        match tag {
+            LEAF_TAG => {
+                let entries: Vec<(i64, u64)> = Vec::des_prefix(payload).unwrap();
+                out.extend(entries);
+            }
+            INTERNAL_TAG => {
+                let entries: Vec<(i64, u32)> = Vec::des_prefix(payload).unwrap();
+                for (_, child_blk) in entries {
+                    collect_all(buf, child_blk, out);
+                }
+            }
+            other => panic!("unknown tag {other}"),
+        }
+    }
+
+    #[test]
+    fn append_rejects_out_of_order_and_duplicate_keys() {
+        let mut builder: DiskBtreeBuilder<i64, u64> = DiskBtreeBuilder::new();
+        builder.append(1, 10).unwrap();
+        builder.append(2, 20).unwrap();
+
+        // Same key again: rejected, not just a debug-only assertion.
+        assert!(builder.append(2, 99).is_err());
+        // Strictly smaller than the last appended key: rejected.
+        assert!(builder.append(0, 0).is_err());
+    }
+
+    #[test]
+    fn empty_tree_has_a_root_leaf() {
+        let builder: DiskBtreeBuilder<i64, u64> = DiskBtreeBuilder::new();
+        let mut buf = Cursor::new(Vec::new());
+        let (root_blk, next_blk) = builder.finish(&mut buf, 0).unwrap();
+        assert_eq!(root_blk, 0);
+        assert_eq!(next_blk, 1);
+
+        let buf = buf.into_inner();
+        let (tag, payload) = read_block(&buf, root_blk);
+        assert_eq!(tag, LEAF_TAG);
+        let entries: Vec<(i64, u64)> = Vec::des(payload).unwrap();
+        assert!(entries.is_empty());
+    }
+
+    #[test]
+    fn single_leaf_round_trips() {
+        let mut builder: DiskBtreeBuilder<i64, u64> = DiskBtreeBuilder::new();
+        let input: Vec<(i64, u64)> = (0..10).map(|i| (i, (i as u64) * 100)).collect();
+        for (k, v) in &input {
+            builder.append(*k, *v).unwrap();
+        }
+
+        let mut buf = Cursor::new(Vec::new());
+        let (root_blk, next_blk) = builder.finish(&mut buf, 0).unwrap();
+        assert_eq!((root_blk, next_blk), (0, 1), "small tree should fit in a single leaf block");
+
+        let buf = buf.into_inner();
+        let mut out = Vec::new();
+        collect_all(&buf, root_blk, &mut out);
+        assert_eq!(out, input);
+    }
+
+    #[test]
+    fn multi_level_tree_round_trips() {
+        let mut builder: DiskBtreeBuilder<i64, u64> = DiskBtreeBuilder::new();
+        // Comfortably more entries than fit in one block at any reasonable
+        // `PAGE_SZ`, to force multiple leaves and at least one internal level.
+        let input: Vec<(i64, u64)> = (0..20_000).map(|i| (i, i as u64)).collect();
+        for (k, v) in &input {
+            builder.append(*k, *v).unwrap();
+        }
+
+        let mut buf = Cursor::new(Vec::new());
+        let (root_blk, next_blk) = builder.finish(&mut buf, 0).unwrap();
+        // Written from block 0 with the root last, so `next_blk` is the total block count.
+        assert!(
+            next_blk > 2,
+            "expected more than one leaf plus a root for {} entries",
+            input.len()
+        );
+
+        let buf = buf.into_inner();
+        let (root_tag, _) = read_block(&buf, root_blk);
+        assert_eq!(root_tag, INTERNAL_TAG);
+
+        let mut out = Vec::new();
+        collect_all(&buf, root_blk, &mut out);
+        assert_eq!(out, input);
+    }
+
+    #[test]
+    fn floor_entry_edge_cases() {
+        let entries: Vec<(i64, u64)> = vec![(1, 10), (3, 30), (5, 50)];
+
+        assert_eq!(floor_entry(&entries, &0), None, "below every key");
+        assert_eq!(floor_entry(&entries, &1), Some(&(1, 10)), "exact match on first");
+        assert_eq!(floor_entry(&entries, &2), Some(&(1, 10)), "between two entries");
+        assert_eq!(floor_entry(&entries, &5), Some(&(5, 50)), "exact match on last");
+        assert_eq!(floor_entry(&entries, &100), Some(&(5, 50)), "above every key");
+
+        let empty: Vec<(i64, u64)> = vec![];
+        assert_eq!(floor_entry(&empty, &0), None, "empty entries");
+    }
+}
@@ -0,0 +1,243 @@
+//! A per-timeline snapshot of the layer map, recording just enough metadata about
+//! each layer -- its `key_range`, `lsn`, `index_start_blk`, format version, and a
+//! content hash -- to identify it without opening and parsing its `Summary`.
+//!
+//! Today the only consumer of this snapshot is the background layer scrubber
+//! (`scrubber.rs`): a layer the manifest already has a matching content hash for is
+//! checked with [`LayerMapManifest::validate_entry`] instead of a full
+//! `ImageLayer::verify_file` pass, and a completed scrub writes a fresh manifest from
+//! what it just verified. Nothing on the timeline-startup path reads this manifest
+//! yet -- reconstructing the in-memory layer set at open still means opening every
+//! layer file and reading its `Summary`, same as before this module existed -- so
+//! wiring `LayerMapManifest::load` into startup (with a fall back to the existing
+//! full scan whenever it returns `None`) to turn that into a single file read remains
+//! unstarted work, not something this module does on its own.
+//!
+//! The manifest is a best-effort cache, not a source of truth: [`LayerMapManifest::load`]
+//! returns `None` if the file is missing or fails to parse, and the caller is expected to
+//! fall back to a full directory scan in that case. Each layer referenced by the manifest
+//! is still validated lazily, the first time it's actually opened -- `ImageLayer::load_inner`
+//! already compares the on-disk `Summary` against what's expected, so a stale or tampered
+//! manifest entry can't make a corrupt layer look valid, it can only cause an extra open.
+use crate::repository::Key;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use zenith_utils::bin_ser::BeSer;
+use zenith_utils::lsn::Lsn;
+
+/// Magic value at the start of every manifest file, so a file of the wrong kind (or a
+/// truncated/corrupt one) is rejected outright instead of misparsed.
+const MANIFEST_MAGIC: u16 = 0x4D4C; // "ML"
+
+/// Format version of the manifest file itself. Bumped independently of
+/// `STORAGE_FORMAT_VERSION`, since the manifest is just a cache of layer metadata, not
+/// a layer file format.
+const MANIFEST_FORMAT_VERSION: u16 = 1;
+
+/// Everything the layer map needs to know about one image layer without opening it.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ManifestEntry {
+    pub filename: String,
+    pub key_range: Range<Key>,
+    pub lsn: Lsn,
+    pub index_start_blk: u32,
+    pub format_version: u16,
+    /// CRC32C of the whole layer file, so a stale manifest entry pointing at a since-
+    /// replaced file is caught even if the replacement happens to have the same name,
+    /// key range and LSN.
+    pub content_hash: u32,
+}
+
+/// A snapshot of one timeline's layer map, as of its last checkpoint.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct LayerMapManifest {
+    magic: u16,
+    format_version: u16,
+
+    pub entries: Vec<ManifestEntry>,
+
+    /// CRC32C of this manifest, computed with this field zeroed out. Always the last
+    /// field, so it covers everything that comes before it.
+    checksum: u32,
+}
+
+impl LayerMapManifest {
+    pub fn new(entries: Vec<ManifestEntry>) -> Self {
+        let mut manifest = LayerMapManifest {
+            magic: MANIFEST_MAGIC,
+            format_version: MANIFEST_FORMAT_VERSION,
+            entries,
+            checksum: 0,
+        };
+        manifest.checksum = manifest.compute_checksum().expect("manifest always serializes");
+        manifest
+    }
+
+    fn compute_checksum(&self) -> Result<u32> {
+        let zeroed = LayerMapManifest {
+            checksum: 0,
+            ..self.clone()
+        };
+        Ok(crc32c::crc32c(&LayerMapManifest::ser(&zeroed)?))
+    }
+
+    /// Path of the manifest file within a timeline's directory.
+    pub fn path(timeline_path: &Path) -> PathBuf {
+        timeline_path.join("layer_map_manifest")
+    }
+
+    /// Atomically (re)write the manifest for a timeline. Called by the scrubber once a
+    /// run has covered the whole timeline. Writes to a temporary file first and renames it
+    /// into place, so a crash mid-write leaves either the old manifest or nothing, never
+    /// a half-written one that could be mistaken for valid.
+    pub fn write(&self, timeline_path: &Path) -> Result<()> {
+        let final_path = Self::path(timeline_path);
+        let tmp_path = final_path.with_extension("tmp");
+
+        let buf = LayerMapManifest::ser(self)
+            .context("failed to serialize layer map manifest")?;
+        std::fs::write(&tmp_path, &buf)
+            .with_context(|| format!("failed to write {}", tmp_path.display()))?;
+        std::fs::rename(&tmp_path, &final_path)
+            .with_context(|| format!("failed to rename {} into place", tmp_path.display()))?;
+        Ok(())
+    }
+
+    /// Load the manifest for a timeline, if one exists and parses cleanly.
+    ///
+    /// Returns `Ok(None)` whenever the manifest can't be trusted -- missing file, parse
+    /// failure, bad magic/checksum, or a format version we don't understand -- so the
+    /// caller can fall back to a full directory scan. Any other I/O error reading the
+    /// file is returned as `Err`.
+    pub fn load(timeline_path: &Path) -> Result<Option<Self>> {
+        let path = Self::path(timeline_path);
+        let buf = match std::fs::read(&path) {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+        };
+
+        let manifest = match LayerMapManifest::des(&buf) {
+            Ok(manifest) => manifest,
+            Err(_) => return Ok(None),
+        };
+
+        if manifest.magic != MANIFEST_MAGIC || manifest.format_version != MANIFEST_FORMAT_VERSION {
+            return Ok(None);
+        }
+        match manifest.compute_checksum() {
+            Ok(expected) if expected == manifest.checksum => Ok(Some(manifest)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Validate that `entry` still matches the file on disk, by content hash. Used by
+    /// the scrubber for layers the manifest already lists; a mismatch means the entry
+    /// is stale, and the scrubber then verifies the file in full instead.
+    pub fn validate_entry(entry: &ManifestEntry, file_bytes: &[u8]) -> Result<()> {
+        let actual_hash = crc32c::crc32c(file_bytes);
+        if actual_hash != entry.content_hash {
+            bail!(
+                "layer map manifest entry for {} is stale: content hash mismatch (manifest {:#x}, actual {:#x})",
+                entry.filename,
+                entry.content_hash,
+                actual_hash,
+            );
+        }
+        Ok(())
+    }
+}
+
+// `LayerMapManifest` only touches `std::fs` and the entry's own fields, none of which
+// need a real `PageServerConf` or `VirtualFile`, so unlike `image_layer.rs` a full
+// round trip through a real directory is practical here.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    /// A fresh, empty directory under the OS temp dir, removed again on drop. There's
+    /// no `tempfile` dependency available in this tree, and no existing precedent for
+    /// one elsewhere in the test code, so this rolls its own rather than adding one.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            static COUNTER: AtomicU32 = AtomicU32::new(0);
+            let n = COUNTER.fetch_add(1, Ordering::SeqCst);
+            let path = std::env::temp_dir().join(format!(
+                "layer_map_manifest-test-{}-{}",
+                std::process::id(),
+                n
+            ));
+            std::fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+
+        fn path(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn sample_entry(filename: &str, content_hash: u32) -> ManifestEntry {
+        ManifestEntry {
+            filename: filename.to_string(),
+            key_range: Key::MIN..Key::MAX,
+            lsn: Lsn(100),
+            index_start_blk: 1,
+            format_version: 1,
+            content_hash,
+        }
+    }
+
+    #[test]
+    fn round_trips_through_a_file() {
+        let dir = TempDir::new();
+        let entries = vec![sample_entry("layer-a", 0x1234), sample_entry("layer-b", 0x5678)];
+        let written = LayerMapManifest::new(entries);
+        written.write(dir.path()).unwrap();
+
+        let loaded = LayerMapManifest::load(dir.path()).unwrap();
+        assert_eq!(loaded, Some(written));
+    }
+
+    #[test]
+    fn load_returns_none_when_no_manifest_exists() {
+        let dir = TempDir::new();
+        assert_eq!(LayerMapManifest::load(dir.path()).unwrap(), None);
+    }
+
+    #[test]
+    fn load_returns_none_for_a_corrupted_file() {
+        let dir = TempDir::new();
+        let manifest = LayerMapManifest::new(vec![sample_entry("layer-a", 0x1234)]);
+        manifest.write(dir.path()).unwrap();
+
+        // Flip a byte in the middle of the written file, simulating on-disk corruption;
+        // the checksum check in `load` should catch it rather than returning a manifest
+        // whose entries no longer match what was actually written.
+        let path = LayerMapManifest::path(dir.path());
+        let mut buf = std::fs::read(&path).unwrap();
+        let mid = buf.len() / 2;
+        buf[mid] ^= 0xff;
+        std::fs::write(&path, &buf).unwrap();
+
+        assert_eq!(LayerMapManifest::load(dir.path()).unwrap(), None);
+    }
+
+    #[test]
+    fn validate_entry_catches_a_content_hash_mismatch() {
+        let entry = sample_entry("layer-a", crc32c::crc32c(b"original bytes"));
+        assert!(LayerMapManifest::validate_entry(&entry, b"original bytes").is_ok());
+        assert!(LayerMapManifest::validate_entry(&entry, b"replaced bytes").is_err());
+    }
+}